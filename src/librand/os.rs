@@ -13,101 +13,240 @@
 
 pub use self::imp::OSRng;
 
-#[cfg(unix)]
+#[cfg(all(unix, not(target_os = "ios")))]
 mod imp {
     use Rng;
     use reader::ReaderRng;
-    use std::io::File;
+    use std::io::{File, IoResult};
+    use std::mem;
 
     /// A random number generator that retrieves randomness straight from
     /// the operating system. Platform sources:
     ///
     /// - Unix-like systems (Linux, Android, Mac OSX): read directly from
-    ///   `/dev/urandom`.
-    /// - Windows: calls `CryptGenRandom`, using the default cryptographic
-    ///   service provider with the `PROV_RSA_FULL` type.
+    ///   `/dev/urandom`, or, on Linux on x86, x86_64, ARM, AArch64 and
+    ///   PowerPC, call the `getrandom(2)` syscall directly if the running
+    ///   kernel supports it, falling back to `/dev/urandom` otherwise.
+    /// - iOS: calls `SecRandomCopyBytes` from the Security framework.
+    /// - Windows: calls `RtlGenRandom`, exported by `advapi32.dll` as
+    ///   `SystemFunction036`.
     ///
     /// This does not block.
-    #[cfg(unix)]
     pub struct OSRng {
-        priv inner: ReaderRng<File>
+        priv inner: OsRngInner
+    }
+
+    enum OsRngInner {
+        OsGetrandomRng,
+        OsReaderRng(ReaderRng<File>),
+    }
+
+    fn new_reader_rng() -> IoResult<OsRngInner> {
+        let reader = try!(File::open(&Path::new("/dev/urandom")));
+        Ok(OsReaderRng(ReaderRng::new(reader)))
     }
 
     impl OSRng {
-        /// Create a new `OSRng`.
-        pub fn new() -> OSRng {
-            let reader = File::open(&Path::new("/dev/urandom"));
-            let reader = reader.ok().expect("Error opening /dev/urandom");
-            let reader_rng = ReaderRng::new(reader);
+        /// Create a new `OSRng`. This method is fallible, and will
+        /// return an error if `/dev/urandom` cannot be opened.
+        #[cfg(not(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "x86", target_arch = "arm", target_arch = "aarch64", target_arch = "powerpc"))))]
+        pub fn new() -> IoResult<OSRng> {
+            let inner = try!(new_reader_rng());
+            Ok(OSRng { inner: inner })
+        }
+
+        /// Create a new `OSRng`. This returns an `IoResult` to match the
+        /// other platform backends, but on these Linux architectures it
+        /// always succeeds: it does not touch `/dev/urandom` at all.
+        /// Bytes are served via the `getrandom(2)` syscall and the file
+        /// is only opened lazily, by `try_fill_bytes`, if the kernel turns
+        /// out not to support it.
+        #[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "x86", target_arch = "arm", target_arch = "aarch64", target_arch = "powerpc")))]
+        pub fn new() -> IoResult<OSRng> {
+            Ok(OSRng { inner: OsGetrandomRng })
+        }
+
+        /// Fill `v` with random bytes. Construction is the only fallible
+        /// part of this path: once the `/dev/urandom` reader is open,
+        /// reads go through `ReaderRng`'s infallible `fill_bytes`, so a
+        /// later read failure still fails the task rather than being
+        /// reported here.
+        #[cfg(not(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "x86", target_arch = "arm", target_arch = "aarch64", target_arch = "powerpc"))))]
+        pub fn try_fill_bytes(&mut self, v: &mut [u8]) -> IoResult<()> {
+            match self.inner {
+                OsReaderRng(ref mut rng) => rng.fill_bytes(v),
+                OsGetrandomRng => fail!("getrandom(2) is only available on linux"),
+            }
+            Ok(())
+        }
 
-            OSRng { inner: reader_rng }
+        /// Fill `v` with random bytes, returning an error rather than
+        /// failing the task if `getrandom(2)` itself reports failure.
+        /// Once this instance has fallen back to the `/dev/urandom`
+        /// reader, reads go through `ReaderRng`'s infallible
+        /// `fill_bytes` instead, so a later read failure there still
+        /// fails the task rather than being reported here.
+        #[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "x86", target_arch = "arm", target_arch = "aarch64", target_arch = "powerpc")))]
+        pub fn try_fill_bytes(&mut self, v: &mut [u8]) -> IoResult<()> {
+            match self.inner {
+                OsReaderRng(ref mut rng) => { rng.fill_bytes(v); return Ok(()); }
+                OsGetrandomRng => {}
+            }
+            if try!(linux::fill_bytes_via_getrandom(v)) {
+                Ok(())
+            } else {
+                // The kernel is too old to know about `getrandom(2)`;
+                // fall back to `/dev/urandom` for the lifetime of this
+                // generator.
+                self.inner = try!(new_reader_rng());
+                self.try_fill_bytes(v)
+            }
         }
     }
 
     impl Rng for OSRng {
         fn next_u32(&mut self) -> u32 {
-            self.inner.next_u32()
+            let mut v = [0u8, .. 4];
+            self.fill_bytes(v);
+            unsafe { mem::transmute(v) }
         }
         fn next_u64(&mut self) -> u64 {
-            self.inner.next_u64()
+            let mut v = [0u8, .. 8];
+            self.fill_bytes(v);
+            unsafe { mem::transmute(v) }
         }
         fn fill_bytes(&mut self, v: &mut [u8]) {
-            self.inner.fill_bytes(v)
+            self.try_fill_bytes(v).unwrap()
+        }
+    }
+
+    #[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "x86", target_arch = "arm", target_arch = "aarch64", target_arch = "powerpc")))]
+    mod linux {
+        use std::io::{IoResult, IoError, OtherIoError};
+        use std::libc::{c_int, c_long, size_t};
+        use std::os;
+
+        extern "C" {
+            fn syscall(number: c_long, ...) -> c_long;
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        static NR_GETRANDOM: c_long = 318;
+        #[cfg(target_arch = "x86")]
+        static NR_GETRANDOM: c_long = 355;
+        #[cfg(target_arch = "arm")]
+        static NR_GETRANDOM: c_long = 384;
+        #[cfg(target_arch = "aarch64")]
+        static NR_GETRANDOM: c_long = 278;
+        #[cfg(target_arch = "powerpc")]
+        static NR_GETRANDOM: c_long = 359;
+
+        static ENOSYS: c_int = 38;
+        static EINTR: c_int = 4;
+
+        fn getrandom(buf: &mut [u8]) -> c_long {
+            unsafe {
+                syscall(NR_GETRANDOM, buf.as_mut_ptr(), buf.len() as size_t, 0u32)
+            }
+        }
+
+        /// Fill `v` using the `getrandom(2)` syscall, looping because a
+        /// single call may return fewer bytes than requested. Returns
+        /// `Ok(false)` if the syscall isn't implemented by the running
+        /// kernel, in which case the caller should fall back to
+        /// `/dev/urandom`.
+        pub fn fill_bytes_via_getrandom(v: &mut [u8]) -> IoResult<bool> {
+            let mut buf = v;
+            while buf.len() > 0 {
+                let ret = getrandom(buf);
+                if ret < 0 {
+                    match os::errno() as c_int {
+                        EINTR => continue,
+                        ENOSYS => return Ok(false),
+                        errno => return Err(IoError {
+                            kind: OtherIoError,
+                            desc: "getrandom(2) failed",
+                            detail: Some(format!("errno {}", errno)),
+                        }),
+                    }
+                } else if ret == 0 {
+                    // The syscall only returns 0 when asked for 0 bytes;
+                    // since `buf` is non-empty here, this would otherwise
+                    // spin forever without making progress.
+                    return Err(IoError {
+                        kind: OtherIoError,
+                        desc: "getrandom(2) returned no bytes",
+                        detail: None,
+                    });
+                } else {
+                    buf = buf.mut_slice_from(ret as uint);
+                }
+            }
+            Ok(true)
         }
     }
 }
 
-#[cfg(windows)]
+#[cfg(target_os = "ios")]
 mod imp {
     use Rng;
     use std::cast;
-    use std::libc::{c_ulong, DWORD, BYTE, LPCSTR, BOOL};
-    use std::os;
-
-    type HCRYPTPROV = c_ulong;
+    use std::io::{IoResult, IoError, OtherIoError};
+    use std::libc::{c_int, c_void, size_t};
 
     /// A random number generator that retrieves randomness straight from
     /// the operating system. Platform sources:
     ///
     /// - Unix-like systems (Linux, Android, Mac OSX): read directly from
-    ///   `/dev/urandom`.
-    /// - Windows: calls `CryptGenRandom`, using the default cryptographic
-    ///   service provider with the `PROV_RSA_FULL` type.
+    ///   `/dev/urandom`, or, on Linux on x86, x86_64, ARM, AArch64 and
+    ///   PowerPC, call the `getrandom(2)` syscall directly if the running
+    ///   kernel supports it, falling back to `/dev/urandom` otherwise.
+    /// - iOS: calls `SecRandomCopyBytes` from the Security framework.
+    /// - Windows: calls `RtlGenRandom`, exported by `advapi32.dll` as
+    ///   `SystemFunction036`.
     ///
     /// This does not block.
-    pub struct OSRng {
-        priv hcryptprov: HCRYPTPROV
-    }
+    ///
+    /// The sandbox iOS apps run in forbids opening `/dev/urandom`
+    /// directly, so this does not share the rest of the Unix family's
+    /// file-based implementation.
+    pub struct OSRng { priv _priv: () }
 
-    static PROV_RSA_FULL: DWORD = 1;
-    static CRYPT_SILENT: DWORD = 64;
-    static CRYPT_VERIFYCONTEXT: DWORD = 0xF0000000;
+    type SecRandomRef = *c_void;
 
-    extern "system" {
-        fn CryptAcquireContextA(phProv: *mut HCRYPTPROV,
-                                pszContainer: LPCSTR,
-                                pszProvider: LPCSTR,
-                                dwProvType: DWORD,
-                                dwFlags: DWORD) -> BOOL;
-        fn CryptGenRandom(hProv: HCRYPTPROV,
-                          dwLen: DWORD,
-                          pbBuffer: *mut BYTE) -> BOOL;
-        fn CryptReleaseContext(hProv: HCRYPTPROV, dwFlags: DWORD) -> BOOL;
+    #[link(name = "Security", kind = "framework")]
+    extern {
+        static kSecRandomDefault: SecRandomRef;
+
+        fn SecRandomCopyBytes(rnd: SecRandomRef,
+                              count: size_t,
+                              bytes: *mut u8) -> c_int;
     }
 
     impl OSRng {
-        /// Create a new `OSRng`.
-        pub fn new() -> OSRng {
-            let mut hcp = 0;
+        /// Create a new `OSRng`. This method is fallible to match the
+        /// other platform backends, though the iOS backend has nothing
+        /// to set up and always succeeds.
+        pub fn new() -> IoResult<OSRng> {
+            Ok(OSRng { _priv: () })
+        }
+
+        /// Fill `v` with random bytes, returning an error rather than
+        /// failing the task if `SecRandomCopyBytes` reports failure.
+        pub fn try_fill_bytes(&mut self, v: &mut [u8]) -> IoResult<()> {
             let ret = unsafe {
-                CryptAcquireContextA(&mut hcp, 0 as LPCSTR, 0 as LPCSTR,
-                                     PROV_RSA_FULL,
-                                     CRYPT_VERIFYCONTEXT | CRYPT_SILENT)
+                SecRandomCopyBytes(kSecRandomDefault, v.len() as size_t,
+                                   v.as_mut_ptr())
             };
             if ret == 0 {
-                fail!("couldn't create context: {}", os::last_os_error());
+                Ok(())
+            } else {
+                Err(IoError {
+                    kind: OtherIoError,
+                    desc: "SecRandomCopyBytes failed",
+                    detail: None,
+                })
             }
-            OSRng { hcryptprov: hcp }
         }
     }
 
@@ -123,26 +262,80 @@ mod imp {
             unsafe { cast::transmute(v) }
         }
         fn fill_bytes(&mut self, v: &mut [u8]) {
-            let ret = unsafe {
-                CryptGenRandom(self.hcryptprov, v.len() as DWORD,
-                               v.as_mut_ptr())
-            };
-            if ret == 0 {
-                fail!("couldn't generate random bytes: {}", os::last_os_error());
-            }
+            self.try_fill_bytes(v).unwrap()
         }
     }
+}
+
+#[cfg(windows)]
+mod imp {
+    use Rng;
+    use std::cast;
+    use std::io::{IoResult, IoError, OtherIoError};
+    use std::libc::{BOOLEAN, ULONG};
+
+    /// A random number generator that retrieves randomness straight from
+    /// the operating system. Platform sources:
+    ///
+    /// - Unix-like systems (Linux, Android, Mac OSX): read directly from
+    ///   `/dev/urandom`, or, on Linux on x86, x86_64, ARM, AArch64 and
+    ///   PowerPC, call the `getrandom(2)` syscall directly if the running
+    ///   kernel supports it, falling back to `/dev/urandom` otherwise.
+    /// - iOS: calls `SecRandomCopyBytes` from the Security framework.
+    /// - Windows: calls `RtlGenRandom`, exported by `advapi32.dll` as
+    ///   `SystemFunction036`.
+    ///
+    /// This does not block.
+    pub struct OSRng { priv _priv: () }
+
+    extern "system" {
+        // Known in documentation as `RtlGenRandom`, but exported from
+        // `advapi32.dll` under this name. Unlike `CryptGenRandom` it
+        // needs no context handle, so there is nothing to acquire or
+        // release per `OSRng` instance.
+        fn SystemFunction036(RandomBuffer: *mut u8, RandomBufferLength: ULONG) -> BOOLEAN;
+    }
 
-    impl Drop for OSRng {
-        fn drop(&mut self) {
+    impl OSRng {
+        /// Create a new `OSRng`. Always succeeds: `RtlGenRandom` needs
+        /// no context handle or other per-instance setup.
+        pub fn new() -> IoResult<OSRng> {
+            Ok(OSRng { _priv: () })
+        }
+
+        /// Fill `v` with random bytes, returning an error rather than
+        /// failing the task if `RtlGenRandom` fails.
+        pub fn try_fill_bytes(&mut self, v: &mut [u8]) -> IoResult<()> {
             let ret = unsafe {
-                CryptReleaseContext(self.hcryptprov, 0)
+                SystemFunction036(v.as_mut_ptr(), v.len() as ULONG)
             };
             if ret == 0 {
-                fail!("couldn't release context: {}", os::last_os_error());
+                Err(IoError {
+                    kind: OtherIoError,
+                    desc: "couldn't generate random bytes via RtlGenRandom",
+                    detail: None,
+                })
+            } else {
+                Ok(())
             }
         }
     }
+
+    impl Rng for OSRng {
+        fn next_u32(&mut self) -> u32 {
+            let mut v = [0u8, .. 4];
+            self.fill_bytes(v);
+            unsafe { cast::transmute(v) }
+        }
+        fn next_u64(&mut self) -> u64 {
+            let mut v = [0u8, .. 8];
+            self.fill_bytes(v);
+            unsafe { cast::transmute(v) }
+        }
+        fn fill_bytes(&mut self, v: &mut [u8]) {
+            self.try_fill_bytes(v).unwrap()
+        }
+    }
 }
 
 #[cfg(test)]
@@ -153,7 +346,7 @@ mod test {
 
     #[test]
     fn test_os_rng() {
-        let mut r = OSRng::new();
+        let mut r = OSRng::new().unwrap();
 
         r.next_u32();
         r.next_u64();
@@ -175,7 +368,7 @@ mod test {
 
                 // deschedule to attempt to interleave things as much
                 // as possible (XXX: is this a good test?)
-                let mut r = OSRng::new();
+                let mut r = OSRng::new().unwrap();
                 task::deschedule();
                 let mut v = [0u8, .. 1000];
 
@@ -195,4 +388,4 @@ mod test {
             tx.send(())
         }
     }
-}
\ No newline at end of file
+}